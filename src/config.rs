@@ -64,6 +64,123 @@ pub struct Conf {
     /// If not specified, the number of threads is equal to the number of CPUs
     #[arg(long)]
     pub threads: Option<u32>,
+
+    /// The number of times to retry a failed command
+    ///
+    /// A command is retried when it matches [retry_on](#structfield.retry_on). The
+    /// default value is 0, which means no retry is performed
+    #[arg(long, default_value = "0")]
+    #[config(default = 0)]
+    pub retries: u32,
+
+    /// The base delay in milliseconds before the first retry
+    ///
+    /// Later retries back off exponentially from this value, see
+    /// [retry_factor](#structfield.retry_factor)
+    #[arg(long, default_value = "200")]
+    #[config(default = 200)]
+    pub retry_base_ms: u64,
+
+    /// The factor the retry delay grows by after each attempt
+    ///
+    /// The delay before retry `n` (0-indexed, so the first retry has `n = 0`)
+    /// is `min(retry_base_ms * retry_factor^n, retry_max_ms)`
+    #[arg(long, default_value = "2.0")]
+    #[config(default = 2.0)]
+    pub retry_factor: f64,
+
+    /// The maximum delay in milliseconds between retries
+    #[arg(long, default_value = "30000")]
+    #[config(default = 30000)]
+    pub retry_max_ms: u64,
+
+    /// Full jitter: sleep a random duration in `[0, delay]` instead of
+    /// exactly `delay` before each retry
+    ///
+    /// This avoids many parallel retries waking up at the same time
+    #[arg(long, default_value = "false")]
+    #[config(default = false)]
+    pub retry_jitter: bool,
+
+    /// Which failures are eligible for retry
+    #[arg(long, value_enum, default_value = "nonzero-exit")]
+    #[config(default = "nonzero-exit")]
+    pub retry_on: RetryOn,
+
+    /// Stop spawning new tasks and kill in-flight ones as soon as one task
+    /// fails or times out
+    #[arg(long, default_value = "false")]
+    #[config(default = false)]
+    pub fail_fast: bool,
+
+    /// How rxec's own exit code is derived from the batch's results
+    #[arg(long, value_enum, default_value = "first-failure")]
+    #[config(default = "first-failure")]
+    pub exit_code_mode: ExitCodeMode,
+
+    /// Write a newline-delimited JSON event stream describing each task's
+    /// lifecycle to this path, or `-` for stdout
+    ///
+    /// If not specified, no event stream is produced
+    #[arg(long)]
+    pub events: Option<String>,
+
+    /// How long to wait after sending SIGTERM on Ctrl-C (or on
+    /// [fail_fast](#structfield.fail_fast)) before killing a still-running
+    /// child
+    #[arg(long, default_value = "5")]
+    #[config(default = 5)]
+    pub shutdown_grace_secs: u64,
+
+    /// How a task's captured stdout and stderr are written to disk
+    #[arg(long, value_enum, default_value = "split")]
+    #[config(default = "split")]
+    pub output_mode: OutputMode,
+}
+
+/// How a task's captured stdout and stderr end up on disk (or the
+/// terminal), see [Conf::output_mode]
+///
+/// Log names include the attempt index of the task's last attempt, as in
+/// `{arg}-{num}-try{attempt}.log`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputMode {
+    /// Write `{arg}-{num}-try{attempt}.out.log` and
+    /// `{arg}-{num}-try{attempt}.err.log`
+    Split,
+    /// Write a single `{arg}-{num}-try{attempt}.log` with stdout and stderr
+    /// interleaved in the order their bytes were read
+    ///
+    /// For a pipeline, only the final stage's output is interleaved; the
+    /// earlier stages' stderr is written before it, in pipeline order
+    Merge,
+    /// Write stdout to `{arg}-{num}-try{attempt}.log`; echo stderr to rxec's
+    /// own stderr, prefixed with the task identifier
+    Combined,
+}
+
+/// How the process exit code is computed once all tasks have finished (or
+/// [fail_fast](Conf::fail_fast) aborted the batch)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitCodeMode {
+    /// Exit with the status code of the first task that failed or timed out
+    FirstFailure,
+    /// Exit with the number of tasks that failed or timed out
+    FailureCount,
+}
+
+/// The kind of failure that triggers a retry
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetryOn {
+    /// Retry only when the command exits with a non-zero status
+    NonzeroExit,
+    /// Retry only when the command hits its timeout
+    Timeout,
+    /// Retry on either a non-zero exit or a timeout
+    Both,
 }
 
 type PartialConf = <Conf as Config>::Partial;
@@ -104,6 +221,17 @@ pub fn load_config(cli: Cli) -> Conf {
         number: Some(cli.conf.number),
         output: cli.conf.output,
         threads: cli.conf.threads,
+        retries: Some(cli.conf.retries),
+        retry_base_ms: Some(cli.conf.retry_base_ms),
+        retry_factor: Some(cli.conf.retry_factor),
+        retry_max_ms: Some(cli.conf.retry_max_ms),
+        retry_jitter: Some(cli.conf.retry_jitter),
+        retry_on: Some(cli.conf.retry_on),
+        fail_fast: Some(cli.conf.fail_fast),
+        exit_code_mode: Some(cli.conf.exit_code_mode),
+        events: cli.conf.events,
+        shutdown_grace_secs: Some(cli.conf.shutdown_grace_secs),
+        output_mode: Some(cli.conf.output_mode),
     };
 
     let conf = Conf::builder()
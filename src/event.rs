@@ -0,0 +1,75 @@
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+/// A single point in a task attempt's lifecycle
+///
+/// Events are written as one JSON object per line to the `--events` stream,
+/// so other tools can follow a batch's progress without scraping log files
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    Started {
+        cmd: String,
+        args: Vec<String>,
+        number: u32,
+        pid: u32,
+    },
+    Finished {
+        arg: String,
+        number: u32,
+        exit_code: i32,
+        duration_ms: u128,
+    },
+    TimedOut {
+        arg: String,
+        number: u32,
+    },
+    Failed {
+        arg: String,
+        number: u32,
+        error: String,
+    },
+}
+
+pub type EventSender = mpsc::UnboundedSender<Event>;
+
+/// Spawn the writer task that serializes events sent over the returned
+/// channel to `path` (or stdout, for `-`) as they arrive
+///
+/// Returns `None` if no `path` was configured, so callers can skip sending
+/// events entirely. Callers must drop every clone of the returned
+/// [EventSender] and await the returned [tokio::task::JoinHandle] before
+/// exiting, or the writer task may never be polled and buffered events will
+/// be lost.
+pub fn spawn_writer(path: Option<String>) -> Option<(EventSender, tokio::task::JoinHandle<()>)> {
+    let path = path?;
+    let (tx, mut rx) = mpsc::unbounded_channel::<Event>();
+
+    let handle = tokio::spawn(async move {
+        let mut writer: Box<dyn tokio::io::AsyncWrite + Unpin + Send> = if path == "-" {
+            Box::new(tokio::io::stdout())
+        } else {
+            match tokio::fs::File::create(&path).await {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    eprintln!("Failed to open events file {path}: {e}");
+                    return;
+                }
+            }
+        };
+
+        while let Some(event) = rx.recv().await {
+            let mut line = serde_json::to_string(&event).expect("Failed to serialize event");
+            line.push('\n');
+
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+            // Flush after every event so long-running parallel batches can
+            // be monitored live instead of only after the writer buffers up.
+            let _ = writer.flush().await;
+        }
+    });
+
+    Some((tx, handle))
+}
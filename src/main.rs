@@ -1,11 +1,32 @@
-use std::{collections::VecDeque, path::PathBuf, process::Stdio, sync::atomic::AtomicUsize};
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use clap::Parser;
 
-use config::{gen_template, load_config, Cli, Conf};
-use tokio::{io::AsyncReadExt, runtime::Runtime, task::JoinSet};
+use config::{gen_template, load_config, Cli, Conf, ExitCodeMode, OutputMode, RetryOn};
+use event::{Event, EventSender};
+use futures::future;
+use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime::Runtime,
+    sync::Notify,
+    task::JoinSet,
+};
+
+/// The exit code conventionally used by shells for a process killed by
+/// Ctrl-C (SIGINT, signal 2)
+const SIGINT_EXIT_CODE: i32 = 130;
 
 mod config;
+mod event;
 
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
@@ -39,15 +60,95 @@ fn main() -> anyhow::Result<()> {
     let rt = rt.build()?;
 
     // Run
-    run(conf, rt)?;
+    let exit_code = run(conf, rt)?;
+    std::process::exit(exit_code);
+}
+
+/// Shared cancellation signal threaded into every in-flight [exec_once] call
+///
+/// `cancelled` lets the `run` loop and not-yet-started tasks notice a
+/// shutdown without racing `notify`, whose wakeups are only delivered to
+/// waiters that are already subscribed when it fires. `via_signal` records
+/// whether the trigger came from Ctrl-C rather than `--fail-fast`, so `run`
+/// only picks the conventional 130 exit code for an actual interrupt.
+#[derive(Clone)]
+struct Shutdown {
+    notify: Arc<Notify>,
+    cancelled: Arc<AtomicBool>,
+    via_signal: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        Shutdown {
+            notify: Arc::new(Notify::new()),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            via_signal: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn is_signalled(&self) -> bool {
+        self.via_signal.load(Ordering::SeqCst)
+    }
 
-    Ok(())
+    /// Cancel every in-flight task's pipeline, same as [Shutdown::trigger_signal]
+    /// but without marking this a Ctrl-C interrupt. Used by `--fail-fast` to
+    /// terminate outstanding children through the same graceful-shutdown path
+    /// instead of `JoinSet::abort_all`, which drops their futures mid-poll and
+    /// orphans the children rather than killing them.
+    fn trigger(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    /// Cancel every in-flight task's pipeline in response to Ctrl-C
+    fn trigger_signal(&self) {
+        self.via_signal.store(true, Ordering::SeqCst);
+        self.trigger();
+    }
+
+    /// Resolve once [Shutdown::trigger] has been called, even if that
+    /// happened before this call started waiting
+    async fn cancelled(&self) {
+        // Subscribe before checking the flag, so a trigger landing between
+        // the two is still delivered to this waiter
+        let notified = self.notify.notified();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Send SIGTERM to a child process, giving it a chance to exit cleanly
+/// before [tokio::process::Child::kill] escalates to SIGKILL
+#[cfg(unix)]
+fn send_sigterm(pid: u32) {
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
 }
 
+#[cfg(not(unix))]
+fn send_sigterm(_pid: u32) {}
+
+/// One stage of a [Task]'s pipeline
 #[derive(Clone, Debug)]
-struct Task {
+struct Stage {
     pub cmd: String,
     pub args: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Task {
+    /// The commands to run, wired stdout-to-stdin in order
+    ///
+    /// Single-command tasks are a pipeline of one stage
+    pub stages: Vec<Stage>,
     pub number: u32,
 }
 
@@ -75,20 +176,81 @@ impl Tasks {
     }
 }
 
-fn run(conf: Conf, rt: Runtime) -> anyhow::Result<()> {
+/// Counts of how a batch of tasks finished, printed as a summary line and
+/// used to derive the process exit code
+#[derive(Default)]
+struct Summary {
+    pub total: u32,
+    pub succeeded: u32,
+    pub failed: u32,
+    pub timed_out: u32,
+    pub first_failure_code: Option<i32>,
+}
+
+impl Summary {
+    fn record(&mut self, res: &TaskResult) {
+        self.total += 1;
+        match res {
+            TaskResult::Finished(_, output) if output.status.success() => self.succeeded += 1,
+            TaskResult::Finished(_, output) => {
+                self.failed += 1;
+                if self.first_failure_code.is_none() {
+                    self.first_failure_code = output.status.code().or(Some(1));
+                }
+            }
+            TaskResult::Failed(_, AttemptError::Timeout) => {
+                self.timed_out += 1;
+                self.first_failure_code.get_or_insert(1);
+            }
+            TaskResult::Failed(_, AttemptError::Cancelled | AttemptError::Other(_)) => {
+                self.failed += 1;
+                self.first_failure_code.get_or_insert(1);
+            }
+        }
+    }
+
+    fn is_failure(res: &TaskResult) -> bool {
+        !matches!(res, TaskResult::Finished(_, output) if output.status.success())
+    }
+
+    fn exit_code(&self, mode: ExitCodeMode) -> i32 {
+        match mode {
+            ExitCodeMode::FirstFailure => self.first_failure_code.unwrap_or(0),
+            ExitCodeMode::FailureCount => (self.failed + self.timed_out) as i32,
+        }
+    }
+}
+
+fn run(conf: Conf, rt: Runtime) -> anyhow::Result<i32> {
+    // A `|` token in `cmd` splits it into pipeline stages, each stage's
+    // stdout feeding the next stage's stdin
+    let stage_tokens: Vec<&[String]> = conf.cmd.split(|t| t == "|").collect();
+    if stage_tokens.iter().any(|tokens| tokens.is_empty()) {
+        anyhow::bail!(
+            "invalid pipeline: empty stage between `|` separators (check for a leading, \
+             trailing, or doubled `|`)"
+        );
+    }
+
     let tasks: VecDeque<Task> = conf
         .args
         .iter()
         .map(|arg| {
-            let cmd = conf.cmd[0].clone();
-            let mut args = conf.cmd[1..].to_vec();
+            let mut stages: Vec<Stage> = stage_tokens
+                .iter()
+                .map(|tokens| Stage {
+                    cmd: tokens[0].clone(),
+                    args: tokens[1..].to_vec(),
+                })
+                .collect();
             if arg != "" {
-                args.push(arg.clone())
+                if let Some(last) = stages.last_mut() {
+                    last.args.push(arg.clone());
+                }
             }
 
             Task {
-                cmd,
-                args,
+                stages,
                 number: conf.number,
             }
         })
@@ -96,7 +258,7 @@ fn run(conf: Conf, rt: Runtime) -> anyhow::Result<()> {
     let mut tasks = Tasks(tasks);
 
     if tasks.is_empty() {
-        return Ok(());
+        return Ok(0);
     }
 
     let interval = if conf.parallel.is_some() {
@@ -111,8 +273,27 @@ fn run(conf: Conf, rt: Runtime) -> anyhow::Result<()> {
             .as_str();
     std::fs::create_dir(&output_path)?;
 
-    rt.block_on(async {
+    let retry = RetryConf::from(&conf);
+    let shutdown = Shutdown::new();
+    let shutdown_grace_secs = conf.shutdown_grace_secs;
+
+    let summary = rt.block_on(async {
+        let (events, events_handle) = match event::spawn_writer(conf.events.clone()) {
+            Some((tx, handle)) => (Some(tx), Some(handle)),
+            None => (None, None),
+        };
+
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    shutdown.trigger_signal();
+                }
+            }
+        });
+
         let mut set = JoinSet::new();
+        let mut summary = Summary::default();
 
         // Push conf.parallel tasks to set
         match conf.parallel {
@@ -120,20 +301,47 @@ fn run(conf: Conf, rt: Runtime) -> anyhow::Result<()> {
                 set.spawn({
                     // Push the first task to set
                     let task = tasks.pop().unwrap();
-                    exec(task, conf.cwd.clone(), conf.timeout, interval)
+                    exec(
+                        task,
+                        conf.cwd.clone(),
+                        conf.timeout,
+                        interval,
+                        retry,
+                        shutdown.clone(),
+                        shutdown_grace_secs,
+                        events.clone(),
+                    )
                 });
             }
             Some(0) => {
                 // Push all tasks to set
                 while let Some(task) = tasks.pop() {
-                    set.spawn(exec(task, conf.cwd.clone(), conf.timeout, interval));
+                    set.spawn(exec(
+                        task,
+                        conf.cwd.clone(),
+                        conf.timeout,
+                        interval,
+                        retry,
+                        shutdown.clone(),
+                        shutdown_grace_secs,
+                        events.clone(),
+                    ));
                 }
             }
             Some(n) => {
                 // Push n tasks to set
                 let mut pushed = 0;
                 while let Some(task) = tasks.pop() {
-                    set.spawn(exec(task, conf.cwd.clone(), conf.timeout, interval));
+                    set.spawn(exec(
+                        task,
+                        conf.cwd.clone(),
+                        conf.timeout,
+                        interval,
+                        retry,
+                        shutdown.clone(),
+                        shutdown_grace_secs,
+                        events.clone(),
+                    ));
                     pushed += 1;
                     if pushed >= n {
                         break;
@@ -144,93 +352,476 @@ fn run(conf: Conf, rt: Runtime) -> anyhow::Result<()> {
 
         // Push tasks if set is not full
         while let Some(res) = set.join_next().await {
-            if !tasks.is_empty() {
+            let Ok(res) = res else {
+                // The task panicked; nothing to record or log.
+                if !shutdown.is_cancelled() && !tasks.is_empty() {
+                    set.spawn(exec(
+                        tasks.pop().unwrap(),
+                        conf.cwd.clone(),
+                        conf.timeout,
+                        interval,
+                        retry,
+                        shutdown.clone(),
+                        shutdown_grace_secs,
+                        events.clone(),
+                    ));
+                }
+                continue;
+            };
+
+            let is_failure = Summary::is_failure(&res);
+            summary.record(&res);
+
+            // Save the output of every finished task, whatever its status
+            match res {
+                TaskResult::Finished(id, output) => {
+                    let log_prefix = format!("{output_path}/{}-{}-try{}", id.arg, id.num, id.attempt);
+
+                    match conf.output_mode {
+                        OutputMode::Split => {
+                            tokio::fs::write(format!("{log_prefix}.out.log"), output.stdout)
+                                .await
+                                .expect("Failed to write stdout");
+                            tokio::fs::write(format!("{log_prefix}.err.log"), output.stderr)
+                                .await
+                                .expect("Failed to write stderr");
+                        }
+                        OutputMode::Merge => {
+                            tokio::fs::write(format!("{log_prefix}.log"), output.merged)
+                                .await
+                                .expect("Failed to write output");
+                        }
+                        OutputMode::Combined => {
+                            tokio::fs::write(format!("{log_prefix}.log"), output.stdout)
+                                .await
+                                .expect("Failed to write stdout");
+                            if !output.stderr.is_empty() {
+                                let mut stderr = tokio::io::stderr();
+                                let prefixed = format!("[{}-{}] ", id.arg, id.num);
+                                let _ = stderr.write_all(prefixed.as_bytes()).await;
+                                let _ = stderr.write_all(&output.stderr).await;
+                                let _ = stderr.flush().await;
+                            }
+                        }
+                    }
+                }
+                TaskResult::Failed(_, _) => {
+                    // TODO: Log
+                }
+            }
+
+            // Trigger the same graceful-shutdown path Ctrl-C uses, rather
+            // than `set.abort_all()`, which would drop in-flight tasks'
+            // futures mid-poll without ever running their kill logic and
+            // orphan their children instead of terminating them.
+            if conf.fail_fast && is_failure && !shutdown.is_cancelled() {
+                shutdown.trigger();
+            }
+
+            if !shutdown.is_cancelled() && !tasks.is_empty() {
                 set.spawn(exec(
                     tasks.pop().unwrap(),
                     conf.cwd.clone(),
                     conf.timeout,
                     interval,
+                    retry,
+                    shutdown.clone(),
+                    shutdown_grace_secs,
+                    events.clone(),
                 ));
             }
+        }
 
-            // Save output if status is not ok
-            match res {
-                Ok(Ok((arg, num, output))) => {
-                    let out_log = format!("{output_path}/{arg}-{num}.log");
+        // Drop the last sender so the writer's `rx.recv()` loop ends, then
+        // wait for it to flush everything it already buffered
+        drop(events);
+        if let Some(handle) = events_handle {
+            let _ = handle.await;
+        }
+
+        summary
+    });
+
+    println!(
+        "total: {}, succeeded: {}, failed: {}, timed-out: {}",
+        summary.total, summary.succeeded, summary.failed, summary.timed_out
+    );
+
+    if shutdown.is_signalled() {
+        return Ok(SIGINT_EXIT_CODE);
+    }
+
+    Ok(summary.exit_code(conf.exit_code_mode))
+}
+
+/// The reason a single attempt of a task did not produce an output
+#[derive(Debug)]
+enum AttemptError {
+    /// The command did not finish within the configured timeout
+    Timeout,
+    /// A shutdown (Ctrl-C or `--fail-fast`) was triggered and the command was
+    /// terminated before it exited
+    Cancelled,
+    /// The command could not be spawned or waited on
+    Other(anyhow::Error),
+}
+
+/// The captured result of a task's pipeline
+///
+/// `merged` additionally interleaves `stdout` and `stderr` in the order
+/// their bytes were actually read, for [OutputMode::Merge]; for a pipeline,
+/// the earlier stages' stderr comes first and only the final stage's output
+/// is interleaved
+struct TaskOutput {
+    pub status: std::process::ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub merged: Vec<u8>,
+}
 
-                    tokio::fs::write(out_log, output.stdout)
-                        .await
-                        .expect("Failed to write output");
+/// Wait for a non-final pipeline stage, draining its piped stderr as it
+/// runs so the stage can't deadlock on a full stderr pipe buffer
+async fn wait_stage(
+    child: &mut tokio::process::Child,
+) -> Result<(std::process::ExitStatus, Vec<u8>), AttemptError> {
+    let mut stderr_pipe = child.stderr.take();
+    let mut errbuf = Vec::new();
+    let stderr_fut = async {
+        if let Some(stderr) = stderr_pipe.as_mut() {
+            let _ = stderr.read_to_end(&mut errbuf).await;
+        }
+    };
+
+    let (wait_res, _) = tokio::join!(child.wait(), stderr_fut);
+    let status = wait_res.map_err(|e| AttemptError::Other(e.into()))?;
+    Ok((status, errbuf))
+}
+
+/// Wait for the final pipeline stage, reading its stdout and stderr pipes
+/// concurrently as they produce data (rather than draining one then the
+/// other) so a child that fills one pipe's buffer can't deadlock rxec, and
+/// so `merged` reflects the real interleaving of the two streams
+async fn wait_final_stage(
+    child: &mut tokio::process::Child,
+) -> Result<(std::process::ExitStatus, Vec<u8>, Vec<u8>, Vec<u8>), AttemptError> {
+    let mut stdout_pipe = child.stdout.take();
+    let mut stderr_pipe = child.stderr.take();
+    let mut stdout_buf = Vec::new();
+    let mut stderr_buf = Vec::new();
+    let mut merged_buf = Vec::new();
+
+    let mut stdout_chunk = [0u8; 8192];
+    let mut stderr_chunk = [0u8; 8192];
+    let mut stdout_open = stdout_pipe.is_some();
+    let mut stderr_open = stderr_pipe.is_some();
+
+    while stdout_open || stderr_open {
+        tokio::select! {
+            res = stdout_pipe.as_mut().unwrap().read(&mut stdout_chunk), if stdout_open => {
+                match res {
+                    Ok(0) | Err(_) => stdout_open = false,
+                    Ok(n) => {
+                        stdout_buf.extend_from_slice(&stdout_chunk[..n]);
+                        merged_buf.extend_from_slice(&stdout_chunk[..n]);
+                    }
                 }
-                _ => {
-                    // TODO: Log
+            }
+            res = stderr_pipe.as_mut().unwrap().read(&mut stderr_chunk), if stderr_open => {
+                match res {
+                    Ok(0) | Err(_) => stderr_open = false,
+                    Ok(n) => {
+                        stderr_buf.extend_from_slice(&stderr_chunk[..n]);
+                        merged_buf.extend_from_slice(&stderr_chunk[..n]);
+                    }
                 }
             }
         }
-    });
+    }
 
-    Ok(())
+    let status = child.wait().await.map_err(|e| AttemptError::Other(e.into()))?;
+    Ok((status, stdout_buf, stderr_buf, merged_buf))
 }
 
-async fn exec(
-    task: Task,
-    cwd: PathBuf,
+/// Drive every stage of a pipeline to completion concurrently, reporting
+/// the first failing stage's exit code if any stage fails
+///
+/// Every stage's stderr is concatenated in pipeline order into the returned
+/// `TaskOutput.stderr`/`.merged`, not just the failing (or final) stage's, so
+/// none of it is silently dropped
+async fn wait_pipeline(children: &mut [tokio::process::Child]) -> Result<TaskOutput, AttemptError> {
+    let (last, interior) = children
+        .split_last_mut()
+        .expect("a pipeline always has at least one stage");
+
+    // Interior stages are drained concurrently with each other (and with the
+    // final stage below) so a later stage filling its stderr pipe buffer
+    // can't block while an earlier stage is still being waited on
+    let interior_fut = future::try_join_all(interior.iter_mut().map(wait_stage));
+
+    let (interior_results, final_result) = tokio::join!(interior_fut, wait_final_stage(last));
+
+    let mut failing = None;
+    let mut interior_stderr = Vec::new();
+    for (status, stderr) in interior_results? {
+        interior_stderr.extend_from_slice(&stderr);
+        if failing.is_none() && !status.success() {
+            failing = Some(status);
+        }
+    }
+
+    let (final_status, final_stdout, final_stderr, final_merged) = final_result?;
+    let status = failing.unwrap_or(final_status);
+
+    let mut stderr = interior_stderr.clone();
+    stderr.extend_from_slice(&final_stderr);
+
+    let mut merged = interior_stderr;
+    merged.extend_from_slice(&final_merged);
+
+    Ok(TaskOutput {
+        status,
+        stdout: final_stdout,
+        stderr,
+        merged,
+    })
+}
+
+/// Run a single attempt of `task`'s pipeline, returning its raw output or an
+/// error if a stage failed to spawn, the pipeline timed out, was cancelled,
+/// or could not be waited on
+///
+/// Emits `Started`/`Finished`/`TimedOut`/`Failed` events on `events`, if set
+async fn exec_once(
+    task: &Task,
+    cwd: &PathBuf,
     timeout: Option<u32>,
-    interval: Option<u32>,
-) -> anyhow::Result<(String, u32, std::process::Output)> {
-    println!("timeout = {:?}", timeout);
+    shutdown: &Shutdown,
+    shutdown_grace_secs: u64,
+    events: Option<&EventSender>,
+) -> Result<TaskOutput, AttemptError> {
+    let arg = task
+        .stages
+        .last()
+        .and_then(|stage| stage.args.last())
+        .cloned()
+        .unwrap_or_default();
+
+    let stage_count = task.stages.len();
+    let mut children = Vec::with_capacity(stage_count);
+    let mut prev_stdout = None;
+
+    for (i, stage) in task.stages.iter().enumerate() {
+        let is_last = i + 1 == stage_count;
+
+        let mut command = tokio::process::Command::new(&stage.cmd);
+        command.args(&stage.args);
+        command.current_dir(cwd);
+        if let Some(stdout) = prev_stdout.take() {
+            command.stdin(Stdio::from(stdout));
+        }
+        command.stdout(Stdio::piped());
+        command.stderr(Stdio::piped());
+
+        let mut child = command.spawn().map_err(|e| AttemptError::Other(e.into()))?;
+
+        if let (Some(events), Some(pid)) = (events, child.id()) {
+            let _ = events.send(Event::Started {
+                cmd: stage.cmd.clone(),
+                args: stage.args.clone(),
+                number: task.number,
+                pid,
+            });
+        }
 
-    let mut command = tokio::process::Command::new(&task.cmd);
-    command.args(&task.args);
-    command.current_dir(cwd);
-    command.stdout(Stdio::piped());
-    let mut child = command.spawn()?;
+        if !is_last {
+            prev_stdout = child.stdout.take();
+        }
+        children.push(child);
+    }
 
+    let started_at = tokio::time::Instant::now();
     let sleep = tokio::time::sleep(tokio::time::Duration::from_secs(timeout.unwrap_or(0) as u64));
     tokio::pin!(sleep);
 
     let res = tokio::select! {
         _ = &mut sleep, if timeout.is_some() => {
-            // Kill the process
-            child.kill().await?;
+            for child in children.iter_mut() {
+                let _ = child.kill().await;
+            }
 
-            Err(anyhow::anyhow!("Timeout"))
+            Err(AttemptError::Timeout)
         }
 
-        res = child.wait() => {
-            if let Ok(status) = res {
-                let mut outbuf = Vec::new();
-                if let Some(mut stdout) = child.stdout.take() {
-                    stdout.read_to_end(&mut outbuf).await?;
+        _ = shutdown.cancelled() => {
+            // Give the pipeline a chance to exit cleanly before escalating
+            for child in &children {
+                if let Some(pid) = child.id() {
+                    send_sigterm(pid);
                 }
+            }
+
+            let grace = tokio::time::sleep(tokio::time::Duration::from_secs(shutdown_grace_secs));
+            tokio::pin!(grace);
 
-                let mut errbuf = Vec::new();
-                if let Some(mut stderr) = child.stderr.take() {
-                    stderr.read_to_end(&mut errbuf).await?;
+            tokio::select! {
+                _ = &mut grace => {
+                    for child in children.iter_mut() {
+                        let _ = child.kill().await;
+                    }
                 }
+                _ = wait_pipeline(&mut children) => {}
+            }
 
-                let output = std::process::Output {
-                    status,
-                    stdout: outbuf,
-                    stderr: errbuf,
-                };
+            Err(AttemptError::Cancelled)
+        }
 
-                Ok(output)
-            } else {
-                Err(anyhow::anyhow!("Failed to wait child process"))
-            }
+        res = wait_pipeline(&mut children) => res,
+    };
+
+    if let Some(events) = events {
+        let event = match &res {
+            Ok(output) => Some(Event::Finished {
+                arg: arg.clone(),
+                number: task.number,
+                exit_code: output.status.code().unwrap_or(-1),
+                duration_ms: started_at.elapsed().as_millis(),
+            }),
+            Err(AttemptError::Timeout) => Some(Event::TimedOut {
+                arg: arg.clone(),
+                number: task.number,
+            }),
+            Err(AttemptError::Cancelled) => Some(Event::Failed {
+                arg: arg.clone(),
+                number: task.number,
+                error: "cancelled".to_string(),
+            }),
+            Err(AttemptError::Other(e)) => Some(Event::Failed {
+                arg: arg.clone(),
+                number: task.number,
+                error: e.to_string(),
+            }),
+        };
+        if let Some(event) = event {
+            let _ = events.send(event);
+        }
+    }
+
+    res
+}
+
+/// Whether a finished attempt should be retried, according to `retry_on`
+fn should_retry(retry_on: RetryOn, res: &Result<TaskOutput, AttemptError>) -> bool {
+    match res {
+        Ok(output) => {
+            matches!(retry_on, RetryOn::NonzeroExit | RetryOn::Both) && !output.status.success()
+        }
+        Err(AttemptError::Timeout) => matches!(retry_on, RetryOn::Timeout | RetryOn::Both),
+        Err(AttemptError::Cancelled) | Err(AttemptError::Other(_)) => false,
+    }
+}
+
+/// The arg, repeat number and attempt index a task result belongs to
+struct TaskId {
+    pub arg: String,
+    pub num: u32,
+    pub attempt: u32,
+}
+
+/// What became of a task's last attempt, once it succeeded, was not eligible
+/// for retry, or its retry budget was exhausted
+enum TaskResult {
+    Finished(TaskId, TaskOutput),
+    Failed(TaskId, AttemptError),
+}
+
+/// The subset of [Conf] that governs retries, copied by value into each
+/// spawned task instead of borrowing `Conf` (which does not outlive a
+/// single call to [run])
+#[derive(Clone, Copy)]
+struct RetryConf {
+    pub retries: u32,
+    pub retry_base_ms: u64,
+    pub retry_factor: f64,
+    pub retry_max_ms: u64,
+    pub retry_jitter: bool,
+    pub retry_on: RetryOn,
+}
+
+impl From<&Conf> for RetryConf {
+    fn from(conf: &Conf) -> Self {
+        RetryConf {
+            retries: conf.retries,
+            retry_base_ms: conf.retry_base_ms,
+            retry_factor: conf.retry_factor,
+            retry_max_ms: conf.retry_max_ms,
+            retry_jitter: conf.retry_jitter,
+            retry_on: conf.retry_on,
+        }
+    }
+}
+
+async fn exec(
+    task: Task,
+    cwd: PathBuf,
+    timeout: Option<u32>,
+    interval: Option<u32>,
+    retry: RetryConf,
+    shutdown: Shutdown,
+    shutdown_grace_secs: u64,
+    events: Option<EventSender>,
+) -> TaskResult {
+    let mut attempt = 0;
+    let res = loop {
+        let res = exec_once(
+            &task,
+            &cwd,
+            timeout,
+            &shutdown,
+            shutdown_grace_secs,
+            events.as_ref(),
+        )
+        .await;
+
+        if attempt >= retry.retries || !should_retry(retry.retry_on, &res) {
+            break res;
         }
+
+        let delay_ms = (retry.retry_base_ms as f64 * retry.retry_factor.powi(attempt as i32))
+            .min(retry.retry_max_ms as f64) as u64;
+        let delay_ms = if retry.retry_jitter {
+            rand::thread_rng().gen_range(0..=delay_ms)
+        } else {
+            delay_ms
+        };
+
+        // Race the backoff sleep against shutdown so Ctrl-C (or fail-fast)
+        // doesn't have to wait out up to `retry_max_ms` before a task
+        // between attempts notices it
+        tokio::select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)) => {}
+            _ = shutdown.cancelled() => break res,
+        }
+
+        attempt += 1;
     };
 
     if let Some(interval) = interval {
         tokio::time::sleep(tokio::time::Duration::from_secs(interval as u64)).await;
     }
 
-    res.map(|output| {
-        (
-            task.args.last().unwrap_or(&"".to_string()).clone(),
-            task.number,
-            output,
-        )
-    })
+    let id = TaskId {
+        arg: task
+            .stages
+            .last()
+            .and_then(|stage| stage.args.last())
+            .cloned()
+            .unwrap_or_default(),
+        num: task.number,
+        attempt,
+    };
+
+    match res {
+        Ok(output) => TaskResult::Finished(id, output),
+        Err(error) => TaskResult::Failed(id, error),
+    }
 }